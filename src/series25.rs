@@ -9,6 +9,53 @@ use core::task::Poll;
 use embedded_hal::blocking::spi::{Transfer, Write};
 use embedded_hal::digital::v2::OutputPin;
 
+/// Errors returned by the 25-series flash driver.
+///
+/// The bus-level variants wrap the error types of the underlying SPI
+/// [`Transfer`]/[`Write`] implementations and the chip-select [`OutputPin`], so
+/// that a genuine bus failure is surfaced to the caller instead of panicking.
+pub enum Error<SPI, CS>
+where
+    SPI: Transfer<u8> + Write<u8>,
+    CS: OutputPin,
+{
+    /// An SPI transfer (read) failed.
+    Transfer(<SPI as Transfer<u8>>::Error),
+
+    /// An SPI write failed.
+    Write(<SPI as Write<u8>>::Error),
+
+    /// The chip-select pin could not be driven.
+    Gpio(CS::Error),
+
+    /// The device was still busy when a ready state was required.
+    Busy,
+
+    /// The targeted region is protected against the requested operation.
+    WriteProtection,
+
+    /// The device could not be configured: it exposes no usable SFDP table,
+    /// or its JEDEC ID is not in the [`devices`] database.
+    Operation,
+}
+
+impl<SPI, CS> fmt::Debug for Error<SPI, CS>
+where
+    SPI: Transfer<u8> + Write<u8>,
+    CS: OutputPin,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Transfer(_) => f.write_str("Error::Transfer"),
+            Error::Write(_) => f.write_str("Error::Write"),
+            Error::Gpio(_) => f.write_str("Error::Gpio"),
+            Error::Busy => f.write_str("Error::Busy"),
+            Error::WriteProtection => f.write_str("Error::WriteProtection"),
+            Error::Operation => f.write_str("Error::Operation"),
+        }
+    }
+}
+
 /// Ready state.
 #[derive(Debug)]
 pub struct Ready {}
@@ -88,6 +135,8 @@ enum Opcode {
     ReadMfDId = 0x90,
     /// Read 16-bit manufacturer ID and 8-bit device ID.
     ReadJedecId = 0x9F,
+    /// Read the factory-programmed 64-bit unique ID.
+    ReadUniqueId = 0x4B,
     /// Set the write enable latch.
     WriteEnable = 0x06,
     /// Clear the write enable latch.
@@ -96,11 +145,115 @@ enum Opcode {
     ReadStatus = 0x05,
     /// Write the 8-bit status register. Not all bits are writeable.
     WriteStatus = 0x01,
+    /// Read the Serial Flash Discoverable Parameters table.
+    ReadSfdp = 0x5A,
     Read = 0x03,
+    /// Fast read on a single I/O line (one dummy byte after the address).
+    FastRead = 0x0B,
     PageProg = 0x02, // directly writes to EEPROMs too
     SectorErase = 0x20,
+    HalfBlockErase = 0x52,
     BlockErase = 0xD8,
     ChipErase = 0xC7,
+    /// Switch the device into 32-bit (4-byte) addressing mode.
+    EnterFourByteMode = 0xB7,
+    /// Switch the device back into 24-bit (3-byte) addressing mode.
+    ExitFourByteMode = 0xE9,
+}
+
+/// Number of address bytes transmitted with each command.
+///
+/// Parts up to 16 MiB use 3-byte addresses; high-density parts such as the
+/// W25Q256/512 class need 4-byte addresses to reach their full capacity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressMode {
+    /// 24-bit addressing (up to 16 MiB).
+    ThreeByte,
+    /// 32-bit addressing (beyond 16 MiB).
+    FourByte,
+}
+
+/// Writes `opcode` followed by `addr` (big-endian) into `buf`, emitting three
+/// or four address bytes depending on `mode`, and returns the number of bytes
+/// written.
+fn serialize_address(mode: AddressMode, opcode: u8, addr: u32, buf: &mut [u8]) -> usize {
+    buf[0] = opcode;
+    match mode {
+        AddressMode::FourByte => {
+            buf[1] = (addr >> 24) as u8;
+            buf[2] = (addr >> 16) as u8;
+            buf[3] = (addr >> 8) as u8;
+            buf[4] = addr as u8;
+            5
+        }
+        AddressMode::ThreeByte => {
+            buf[1] = (addr >> 16) as u8;
+            buf[2] = (addr >> 8) as u8;
+            buf[3] = addr as u8;
+            4
+        }
+    }
+}
+
+/// Decodes a Basic Flash Parameter Table (given as its little-endian DWORDs)
+/// into an [`InternalSizes`].
+///
+/// Returns `None` if the table is too short to carry a density word or exposes
+/// no usable erase type, and ignores erase-type entries whose size exponent is
+/// out of range rather than shifting past the width of `usize`.
+fn decode_bfpt(dwords: &[u32]) -> Option<InternalSizes> {
+    // 2nd DWORD: flash density in bits.
+    let density = *dwords.get(1)?;
+    let bits: u64 = if density & (1 << 31) == 0 {
+        u64::from(density) + 1
+    } else {
+        1u64 << (density & 0x7FFF_FFFF)
+    };
+    let chip_size = (bits / 8) as usize;
+
+    // Erase types live in the 8th and 9th DWORDs, two per DWORD: each is a size
+    // exponent (`2^N` bytes) in the low byte paired with its opcode in the next.
+    // A zero or out-of-range exponent marks an unused slot.
+    let mut erase = [(0usize, 0u8); 4];
+    let mut found = 0;
+    for (dw, shift) in [(7, 0), (7, 16), (8, 0), (8, 16)] {
+        let word = dwords.get(dw).copied().unwrap_or(0) >> shift;
+        let n = (word & 0xFF) as u32;
+        if n == 0 || n >= usize::BITS {
+            continue;
+        }
+        let opcode = ((word >> 8) & 0xFF) as u8;
+        erase[found] = (1usize << n, opcode);
+        found += 1;
+    }
+    let erase = &erase[..found];
+    // Smallest erase is the sector; largest is the block.
+    let (sector_size, sector_opcode) = *erase.iter().min_by_key(|(s, _)| *s)?;
+    let (block_size, block_opcode) = *erase.iter().max_by_key(|(s, _)| *s)?;
+    // The half-block is the largest erase granularity below the full block.
+    let (half_block_size, half_block_opcode) = erase
+        .iter()
+        .filter(|(s, _)| *s < block_size)
+        .max_by_key(|(s, _)| *s)
+        .copied()
+        .unwrap_or((0, 0));
+
+    // 11th DWORD bits [7:4]: page size as `2^N` bytes (default 256).
+    let page_size = match dwords.get(10) {
+        Some(dw) => 1usize << ((dw >> 4) & 0xF),
+        None => 256,
+    };
+
+    Some(InternalSizes {
+        page_size,
+        sector_size,
+        half_block_size,
+        block_size,
+        chip_size,
+        sector_opcode,
+        half_block_opcode,
+        block_opcode,
+    })
 }
 
 bitflags! {
@@ -117,12 +270,191 @@ bitflags! {
     }
 }
 
-#[derive(Debug)]
+/// The portion of the array protected against program and erase operations.
+///
+/// The block-protect bits (`BP2`/`BP1`/`BP0`) in the status register protect a
+/// fraction of the chip, growing from the top of the address space: `None`
+/// leaves the whole device writable, the `Upper*` variants lock an increasing
+/// fraction, and `All` protects the entire chip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ProtectionRegion {
+    /// No region protected.
+    None = 0b000,
+    /// Upper 1/64th protected.
+    Upper64th = 0b001,
+    /// Upper 1/32nd protected.
+    Upper32nd = 0b010,
+    /// Upper 1/16th protected.
+    Upper16th = 0b011,
+    /// Upper 1/8th protected.
+    Upper8th = 0b100,
+    /// Upper 1/4th protected.
+    Upper4th = 0b101,
+    /// Upper half protected.
+    UpperHalf = 0b110,
+    /// Whole chip protected.
+    All = 0b111,
+}
+
+impl ProtectionRegion {
+    /// Decodes a region from the 3-bit block-protect field.
+    fn from_bp(bp: u8) -> ProtectionRegion {
+        match bp & 0b111 {
+            0b000 => ProtectionRegion::None,
+            0b001 => ProtectionRegion::Upper64th,
+            0b010 => ProtectionRegion::Upper32nd,
+            0b011 => ProtectionRegion::Upper16th,
+            0b100 => ProtectionRegion::Upper8th,
+            0b101 => ProtectionRegion::Upper4th,
+            0b110 => ProtectionRegion::UpperHalf,
+            _ => ProtectionRegion::All,
+        }
+    }
+
+    /// The status-register byte that selects this protection region, with all
+    /// other (non-`PROT`) bits cleared.
+    fn to_status_bits(self) -> u8 {
+        ((self as u8) << 2) & Status::PROT.bits()
+    }
+
+    /// Number of bytes protected at the top of a `chip_size`-byte array.
+    fn protected_bytes(self, chip_size: usize) -> usize {
+        match self {
+            ProtectionRegion::None => 0,
+            ProtectionRegion::Upper64th => chip_size / 64,
+            ProtectionRegion::Upper32nd => chip_size / 32,
+            ProtectionRegion::Upper16th => chip_size / 16,
+            ProtectionRegion::Upper8th => chip_size / 8,
+            ProtectionRegion::Upper4th => chip_size / 4,
+            ProtectionRegion::UpperHalf => chip_size / 2,
+            ProtectionRegion::All => chip_size,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct InternalSizes {
     pub page_size: usize,
     pub sector_size: usize,
+    pub half_block_size: usize,
     pub block_size: usize,
     pub chip_size: usize,
+    /// Opcode used to erase a single `sector_size` region.
+    pub sector_opcode: u8,
+    /// Opcode used to erase a single `half_block_size` region.
+    pub half_block_opcode: u8,
+    /// Opcode used to erase a single `block_size` region.
+    pub block_opcode: u8,
+}
+
+/// Known-device database mapping JEDEC IDs to geometry and capabilities.
+///
+/// Callers that know which part is on the board can use [`init`] with an
+/// explicit [`InternalSizes`]; those that don't can let [`init_autodetect`]
+/// read the JEDEC ID and consult this table instead.
+///
+/// [`init`]: Flash::init
+/// [`init_autodetect`]: Flash::init_autodetect
+pub mod devices {
+    use super::InternalSizes;
+
+    /// The optional features a recognized chip supports.
+    #[derive(Debug, Clone, Copy)]
+    pub struct Capabilities {
+        /// 4 KiB sector erase is available.
+        pub sector_erase: bool,
+        /// 32 KiB half-block erase is available.
+        pub half_block_erase: bool,
+        /// 64 KiB block erase is available.
+        pub block_erase: bool,
+        /// The part needs 4-byte (32-bit) addressing to reach its full
+        /// capacity; `init_autodetect` switches it into that mode.
+        pub four_byte_addressing: bool,
+        /// Single-lane Fast Read is available.
+        pub fast_read: bool,
+        /// Dual Output Fast Read is available.
+        pub dual_read: bool,
+        /// Quad Output Fast Read is available.
+        pub quad_read: bool,
+    }
+
+    /// A recognized flash chip: its JEDEC key, geometry and capabilities.
+    #[derive(Debug, Clone, Copy)]
+    pub struct Device {
+        /// JEDEC manufacturer code.
+        pub mfr_code: u8,
+        /// Manufacturer-specific 2-byte device ID (memory type and capacity).
+        pub device_id: [u8; 2],
+        /// Geometry to hand to the driver.
+        pub sizes: InternalSizes,
+        /// Supported optional features.
+        pub capabilities: Capabilities,
+    }
+
+    /// The common W25Q-family capability set (everything but 4-byte addressing).
+    const W25Q: Capabilities = Capabilities {
+        sector_erase: true,
+        half_block_erase: true,
+        block_erase: true,
+        four_byte_addressing: false,
+        fast_read: true,
+        dual_read: true,
+        quad_read: true,
+    };
+
+    const fn w25q_sizes(chip_size: usize) -> InternalSizes {
+        InternalSizes {
+            page_size: 256,
+            sector_size: 4 * 1024,
+            half_block_size: 32 * 1024,
+            block_size: 64 * 1024,
+            chip_size,
+            sector_opcode: 0x20,
+            half_block_opcode: 0x52,
+            block_opcode: 0xD8,
+        }
+    }
+
+    /// Table of known parts, keyed on `(mfr_code, device_id)`.
+    static DEVICES: &[Device] = &[
+        Device {
+            mfr_code: 0xEF,
+            device_id: [0x40, 0x16],
+            sizes: w25q_sizes(4 * 1024 * 1024),
+            capabilities: W25Q,
+        },
+        Device {
+            mfr_code: 0xEF,
+            device_id: [0x40, 0x17],
+            sizes: w25q_sizes(8 * 1024 * 1024),
+            capabilities: W25Q,
+        },
+        Device {
+            mfr_code: 0xEF,
+            device_id: [0x40, 0x18],
+            sizes: w25q_sizes(16 * 1024 * 1024),
+            capabilities: W25Q,
+        },
+        Device {
+            mfr_code: 0xEF,
+            device_id: [0x40, 0x19],
+            sizes: w25q_sizes(32 * 1024 * 1024),
+            capabilities: Capabilities {
+                four_byte_addressing: true,
+                ..W25Q
+            },
+        },
+    ];
+
+    /// Looks up a chip by its JEDEC manufacturer code and device ID.
+    ///
+    /// Returns `None` if the ID is not in the table.
+    pub fn lookup(mfr_code: u8, device_id: &[u8]) -> Option<&'static Device> {
+        DEVICES
+            .iter()
+            .find(|d| d.mfr_code == mfr_code && d.device_id == device_id)
+    }
 }
 
 /// Driver for 25-series SPI Flash chips.
@@ -138,10 +470,11 @@ pub struct Flash<SPI: Transfer<u8>, CS: OutputPin, STATE> {
     spi: SPI,
     cs: CS,
     sizes: InternalSizes,
+    address_mode: AddressMode,
     state: STATE,
 }
 
-impl<SPI: Transfer<u8>, CS: OutputPin, STATE> Flash<SPI, CS, STATE> {
+impl<SPI: Transfer<u8> + Write<u8>, CS: OutputPin, STATE> Flash<SPI, CS, STATE> {
     /// Creates a new 25-series flash driver.
     ///
     /// # Parameters
@@ -150,70 +483,101 @@ impl<SPI: Transfer<u8>, CS: OutputPin, STATE> Flash<SPI, CS, STATE> {
     ///   mode for the device.
     /// * **`cs`**: The **C**hip-**S**elect Pin connected to the `\CS`/`\CE` pin
     ///   of the flash chip. Will be driven low when accessing the device.
-    pub fn init(spi: SPI, cs: CS, sizes: InternalSizes) -> Flash<SPI, CS, Ready> {
+    pub fn init(
+        spi: SPI,
+        cs: CS,
+        sizes: InternalSizes,
+    ) -> Result<Flash<SPI, CS, Ready>, Error<SPI, CS>> {
         let mut this = Flash {
             spi,
             cs,
             sizes,
+            address_mode: AddressMode::ThreeByte,
             state: Ready {},
         };
 
         // If the MCU is reset and an old operation is still ongoing, wait for it to finish.
-        while this.read_status().contains(Status::BUSY) {}
+        while this.read_status()?.contains(Status::BUSY) {}
 
-        this
+        Ok(this)
     }
 
-    fn command(&mut self, bytes: &mut [u8]) {
-        // If the SPI transfer fails, make sure to disable CS anyways
-        if self.cs.set_low().is_err() {
-            panic!("flash panic");
-        }
-        if self.spi.transfer(bytes).is_err() {
-            panic!("flash panic");
-        }
-        if self.cs.set_high().is_err() {
-            panic!("flash panic");
-        }
+    /// Writes `opcode` and `addr` into `buf` in the driver's current
+    /// [`AddressMode`], returning the number of bytes written.
+    fn serialize_address(&self, opcode: u8, addr: u32, buf: &mut [u8]) -> usize {
+        serialize_address(self.address_mode, opcode, addr, buf)
+    }
+
+    fn command(&mut self, bytes: &mut [u8]) -> Result<(), Error<SPI, CS>> {
+        // Make sure to deassert CS even if the transfer fails.
+        self.cs.set_low().map_err(Error::Gpio)?;
+        let spi_result = self.spi.transfer(bytes).map(drop).map_err(Error::Transfer);
+        let cs_result = self.cs.set_high().map_err(Error::Gpio);
+        spi_result?;
+        cs_result
     }
 
     /// Reads the JEDEC manufacturer/device identification.
-    pub fn read_jedec_id(&mut self) -> Identification {
+    pub fn read_jedec_id(&mut self) -> Result<Identification, Error<SPI, CS>> {
         // Optimistically read 12 bytes, even though some identifiers will be shorter
         let mut buf: [u8; 12] = [0; 12];
         buf[0] = Opcode::ReadJedecId as u8;
-        self.command(&mut buf);
+        self.command(&mut buf)?;
 
         // Skip buf[0] (SPI read response byte)
-        Identification::from_jedec_id(&buf[1..])
+        Ok(Identification::from_jedec_id(&buf[1..]))
+    }
+
+    /// Reads the factory-programmed 64-bit unique ID.
+    ///
+    /// Winbond-style W25Q parts (among others) expose a unique per-die serial
+    /// number. The command byte is followed by four dummy bytes, after which
+    /// the device clocks out the 8-byte identifier. This is useful for device
+    /// provisioning and anti-cloning, and complements [`read_jedec_id`].
+    ///
+    /// [`read_jedec_id`]: Self::read_jedec_id
+    pub fn read_unique_id(&mut self) -> Result<[u8; 8], Error<SPI, CS>> {
+        // Command + 4 dummy bytes, then 8 bytes of serial number.
+        let mut buf = [0u8; 13];
+        buf[0] = Opcode::ReadUniqueId as u8;
+        self.command(&mut buf)?;
+
+        let mut id = [0u8; 8];
+        id.copy_from_slice(&buf[5..13]);
+        Ok(id)
     }
 
     /// Reads the status register.
-    pub fn read_status(&mut self) -> Status {
+    pub fn read_status(&mut self) -> Result<Status, Error<SPI, CS>> {
         let mut buf = [Opcode::ReadStatus as u8, 0];
-        self.command(&mut buf);
+        self.command(&mut buf)?;
+
+        Ok(Status::from_bits_truncate(buf[1]))
+    }
 
-        Status::from_bits_truncate(buf[1])
+    /// Returns the addressing mode the driver is currently using.
+    pub fn address_mode(&self) -> AddressMode {
+        self.address_mode
     }
 
-    fn write_enable(&mut self) {
+    fn write_enable(&mut self) -> Result<(), Error<SPI, CS>> {
         let mut cmd_buf = [Opcode::WriteEnable as u8];
-        self.command(&mut cmd_buf);
+        self.command(&mut cmd_buf)
     }
 }
 
-impl<SPI: Transfer<u8>, CS: OutputPin> Flash<SPI, CS, Busy> {
-    pub fn wait(&mut self) -> Poll<()> {
+impl<SPI: Transfer<u8> + Write<u8>, CS: OutputPin> Flash<SPI, CS, Busy> {
+    pub fn wait(&mut self) -> Result<Poll<()>, Error<SPI, CS>> {
         // TODO: Consider changing this to a delay based pattern
-        let status = self.read_status();
+        let status = self.read_status()?;
 
         if status.contains(Status::BUSY) {
-            return Poll::Pending;
+            return Ok(Poll::Pending);
         }
 
         self.state.done = true;
 
-        Poll::Ready(())
+        Ok(Poll::Ready(()))
     }
 
     pub fn finish_waiting(self) -> Flash<SPI, CS, Ready> {
@@ -223,46 +587,259 @@ impl<SPI: Transfer<u8>, CS: OutputPin> Flash<SPI, CS, Busy> {
             spi: self.spi,
             cs: self.cs,
             sizes: self.sizes,
+            address_mode: self.address_mode,
             state: Ready {},
         }
     }
 }
 
 impl<SPI: Transfer<u8> + Write<u8>, CS: OutputPin> Flash<SPI, CS, Ready> {
+    /// Creates a driver whose geometry is discovered at runtime via SFDP.
+    ///
+    /// Instead of hand-constructing [`InternalSizes`], this reads the JEDEC
+    /// Serial Flash Discoverable Parameters table from the device and derives
+    /// the page, sector, block and chip sizes from the Basic Flash Parameter
+    /// Table. Returns [`Error::Operation`] if the chip does not expose a valid
+    /// SFDP signature.
+    ///
+    /// # Parameters
+    ///
+    /// * **`spi`**: An SPI master configured for the device.
+    /// * **`cs`**: The chip-select pin, driven low while accessing the device.
+    pub fn init_with_sfdp(spi: SPI, cs: CS) -> Result<Flash<SPI, CS, Ready>, Error<SPI, CS>> {
+        let mut this = Flash {
+            spi,
+            cs,
+            sizes: InternalSizes {
+                page_size: 0,
+                sector_size: 0,
+                half_block_size: 0,
+                block_size: 0,
+                chip_size: 0,
+                sector_opcode: 0,
+                half_block_opcode: 0,
+                block_opcode: 0,
+            },
+            address_mode: AddressMode::ThreeByte,
+            state: Ready {},
+        };
+
+        this.sizes = this.discover_sfdp()?;
+
+        // If an old operation is still ongoing, wait for it to finish.
+        while this.read_status()?.contains(Status::BUSY) {}
+
+        Ok(this)
+    }
+
+    /// Creates a driver by recognizing the chip from its JEDEC ID.
+    ///
+    /// Reads the JEDEC manufacturer/device identification, looks it up in the
+    /// [`devices`] database and configures the geometry from the matching
+    /// entry. Returns [`Error::Operation`] if the ID is not recognized; callers
+    /// that expect an unlisted part should use [`init`](Self::init) with an
+    /// explicit [`InternalSizes`] or [`init_with_sfdp`](Self::init_with_sfdp).
+    ///
+    /// Chips flagged as needing 4-byte addressing are switched into it before
+    /// the driver is returned, so addresses above 16 MiB are transmitted in
+    /// full. The recognized chip's [`Capabilities`](devices::Capabilities) are
+    /// returned alongside the driver.
+    pub fn init_autodetect(
+        spi: SPI,
+        cs: CS,
+    ) -> Result<(Flash<SPI, CS, Ready>, devices::Capabilities), Error<SPI, CS>> {
+        let mut this = Flash {
+            spi,
+            cs,
+            sizes: InternalSizes {
+                page_size: 0,
+                sector_size: 0,
+                half_block_size: 0,
+                block_size: 0,
+                chip_size: 0,
+                sector_opcode: 0,
+                half_block_opcode: 0,
+                block_opcode: 0,
+            },
+            address_mode: AddressMode::ThreeByte,
+            state: Ready {},
+        };
+
+        let id = this.read_jedec_id()?;
+        let device = *devices::lookup(id.mfr_code(), id.device_id()).ok_or(Error::Operation)?;
+        this.sizes = device.sizes;
+
+        // High-density parts must be switched into 4-byte addressing, otherwise
+        // every command above 16 MiB would emit a truncated 24-bit address.
+        if device.capabilities.four_byte_addressing {
+            this.enter_four_byte_mode()?;
+        }
+
+        // If an old operation is still ongoing, wait for it to finish.
+        while this.read_status()?.contains(Status::BUSY) {}
+
+        Ok((this, device.capabilities))
+    }
+
+    /// Reads and parses the SFDP tables, returning the discovered geometry.
+    fn discover_sfdp(&mut self) -> Result<InternalSizes, Error<SPI, CS>> {
+        // SFDP header: 4-byte signature, revision, number of parameter headers.
+        let mut header = [0u8; 8];
+        self.read_dummy(Opcode::ReadSfdp, 0, &mut header)?;
+        if &header[0..4] != b"SFDP" {
+            return Err(Error::Operation);
+        }
+        let nph = header[6]; // zero-based count of *additional* headers
+
+        // Walk the parameter headers looking for the Basic Flash Parameter Table.
+        let mut bfpt_ptr = None;
+        let mut bfpt_len = 0usize;
+        for i in 0..=u32::from(nph) {
+            let mut ph = [0u8; 8];
+            self.read_dummy(Opcode::ReadSfdp, 8 + i * 8, &mut ph)?;
+            let id = (u16::from(ph[7]) << 8) | u16::from(ph[0]);
+            if id == 0xFF00 {
+                bfpt_len = usize::from(ph[3]); // length in DWORDs
+                bfpt_ptr =
+                    Some(u32::from(ph[4]) | (u32::from(ph[5]) << 8) | (u32::from(ph[6]) << 16));
+                break;
+            }
+        }
+
+        let ptr = bfpt_ptr.ok_or(Error::Operation)?;
+
+        // Read the whole BFPT (capped to a sane maximum) as little-endian DWORDs.
+        let count = bfpt_len.min(16);
+        let mut raw = [0u8; 64];
+        self.read_dummy(Opcode::ReadSfdp, ptr, &mut raw[..count * 4])?;
+        let mut dwords = [0u32; 16];
+        for (i, dw) in dwords.iter_mut().enumerate().take(count) {
+            let b = i * 4;
+            *dw = u32::from(raw[b])
+                | (u32::from(raw[b + 1]) << 8)
+                | (u32::from(raw[b + 2]) << 16)
+                | (u32::from(raw[b + 3]) << 24);
+        }
+
+        decode_bfpt(&dwords[..count]).ok_or(Error::Operation)
+    }
+
+    /// Switches the device and driver into 4-byte (32-bit) addressing mode.
+    ///
+    /// Subsequent reads, writes and erases will transmit four address bytes,
+    /// which is required to reach the full capacity of parts larger than
+    /// 16 MiB such as the W25Q256/512 class.
+    pub fn enter_four_byte_mode(&mut self) -> Result<(), Error<SPI, CS>> {
+        let mut cmd_buf = [Opcode::EnterFourByteMode as u8];
+        self.command(&mut cmd_buf)?;
+        self.address_mode = AddressMode::FourByte;
+        Ok(())
+    }
+
+    /// Switches the device and driver back into 3-byte (24-bit) addressing mode.
+    pub fn exit_four_byte_mode(&mut self) -> Result<(), Error<SPI, CS>> {
+        let mut cmd_buf = [Opcode::ExitFourByteMode as u8];
+        self.command(&mut cmd_buf)?;
+        self.address_mode = AddressMode::ThreeByte;
+        Ok(())
+    }
+
+    /// Returns [`Error::Busy`] if a program/erase is still in progress, so that
+    /// callers don't start a new operation on a device that cannot accept it.
+    fn ensure_ready(&mut self) -> Result<(), Error<SPI, CS>> {
+        if self.read_status()?.contains(Status::BUSY) {
+            return Err(Error::Busy);
+        }
+        Ok(())
+    }
+
+    /// Returns [`Error::WriteProtection`] if `[addr, addr + len)` overlaps the
+    /// region locked by the current block-protection bits.
+    fn ensure_writable(&mut self, addr: u32, len: usize) -> Result<(), Error<SPI, CS>> {
+        let protected = self.protection()?.protected_bytes(self.sizes.chip_size);
+        if protected == 0 {
+            return Ok(());
+        }
+        let guard_start = self.sizes.chip_size.saturating_sub(protected);
+        if addr as usize + len > guard_start {
+            return Err(Error::WriteProtection);
+        }
+        Ok(())
+    }
+
     /// Reads flash contents into `buf`, starting at `addr`.
     ///
     /// Note that `addr` is not fully decoded: Flash chips will typically only
     /// look at the lowest `N` bits needed to encode their size, which means
     /// that the contents are "mirrored" to addresses that are a multiple of the
-    /// flash size. Only 24 bits of `addr` are transferred to the device in any
-    /// case, limiting the maximum size of 25-series SPI flash chips to 16 MiB.
+    /// flash size. In 3-byte addressing mode only 24 bits of `addr` are
+    /// transferred to the device, limiting the maximum size of 25-series SPI
+    /// flash chips to 16 MiB; switch to 4-byte mode with
+    /// [`enter_four_byte_mode`](Self::enter_four_byte_mode) to address beyond.
     ///
     /// # Parameters
     ///
     /// * `addr`: 24-bit address to start reading at.
     /// * `buf`: Destination buffer to fill.
-    pub fn read(&mut self, addr: u32, buf: &mut [u8]) {
+    pub fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<SPI, CS>> {
         // TODO what happens if `buf` is empty?
 
-        let mut cmd_buf = [
-            Opcode::Read as u8,
-            (addr >> 16) as u8,
-            (addr >> 8) as u8,
-            addr as u8,
-        ];
+        let mut cmd_buf = [0u8; 5];
+        let len = self.serialize_address(Opcode::Read as u8, addr, &mut cmd_buf);
 
-        if self.cs.set_low().is_err() {
-            panic!("flash panic");
-        }
-        if self.spi.transfer(&mut cmd_buf).is_err() {
-            panic!("flash panic");
-        }
-        if self.spi.transfer(buf).is_err() {
-            panic!("flash panic");
-        }
-        if self.cs.set_high().is_err() {
-            panic!("flash panic");
-        }
+        // Make sure to deassert CS even if a transfer fails.
+        self.cs.set_low().map_err(Error::Gpio)?;
+        let spi_result = self
+            .spi
+            .transfer(&mut cmd_buf[..len])
+            .and_then(|_| self.spi.transfer(buf))
+            .map(drop)
+            .map_err(Error::Transfer);
+        let cs_result = self.cs.set_high().map_err(Error::Gpio);
+        spi_result?;
+        cs_result
+    }
+
+    /// Reads flash contents into `buf` using the Fast Read command.
+    ///
+    /// Fast Read behaves like [`read`](Self::read) but inserts a single dummy
+    /// byte between the address and the first data byte, which lets the
+    /// chip be clocked at its full rated frequency instead of the lower rate
+    /// required by the plain read command.
+    ///
+    /// # Parameters
+    ///
+    /// * `addr`: Address to start reading at, sent as 3 or 4 bytes according
+    ///   to the current [`AddressMode`].
+    /// * `buf`: Destination buffer to fill.
+    pub fn read_fast(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<SPI, CS>> {
+        self.read_dummy(Opcode::FastRead, addr, buf)
+    }
+
+    /// Shared implementation for single-lane reads that need a dummy byte:
+    /// command, address and a single dummy byte, followed by the data phase.
+    fn read_dummy(
+        &mut self,
+        opcode: Opcode,
+        addr: u32,
+        buf: &mut [u8],
+    ) -> Result<(), Error<SPI, CS>> {
+        let mut cmd_buf = [0u8; 6];
+        let len = self.serialize_address(opcode as u8, addr, &mut cmd_buf);
+        cmd_buf[len] = 0; // single dummy byte
+        let len = len + 1;
+
+        // Make sure to deassert CS even if a transfer fails.
+        self.cs.set_low().map_err(Error::Gpio)?;
+        let spi_result = self
+            .spi
+            .transfer(&mut cmd_buf[..len])
+            .and_then(|_| self.spi.transfer(buf))
+            .map(drop)
+            .map_err(Error::Transfer);
+        let cs_result = self.cs.set_high().map_err(Error::Gpio);
+        spi_result?;
+        cs_result
     }
 
     /// Erases sectors from the memory chip.
@@ -270,28 +847,31 @@ impl<SPI: Transfer<u8> + Write<u8>, CS: OutputPin> Flash<SPI, CS, Ready> {
     /// # Parameters
     /// * `addr`: The address to start erasing at. If the address is not on a sector boundary,
     ///   the lower bits can be ignored in order to make it fit.
-    pub fn erase_sectors(mut self, addr: u32, amount: usize) -> Flash<SPI, CS, Busy> {
+    pub fn erase_sectors(
+        mut self,
+        addr: u32,
+        amount: usize,
+    ) -> Result<Flash<SPI, CS, Busy>, Error<SPI, CS>> {
+        self.ensure_ready()?;
+        self.ensure_writable(addr, amount * self.sizes.sector_size)?;
         for c in 0..amount {
-            self.write_enable();
+            self.write_enable()?;
 
             let current_addr: u32 = (addr as usize + c * self.sizes.sector_size)
                 .try_into()
                 .unwrap();
-            let mut cmd_buf = [
-                Opcode::SectorErase as u8,
-                (current_addr >> 16) as u8,
-                (current_addr >> 8) as u8,
-                current_addr as u8,
-            ];
-            self.command(&mut cmd_buf);
+            let mut cmd_buf = [0u8; 5];
+            let len = self.serialize_address(self.sizes.sector_opcode, current_addr, &mut cmd_buf);
+            self.command(&mut cmd_buf[..len])?;
         }
 
-        Flash {
+        Ok(Flash {
             spi: self.spi,
             cs: self.cs,
             sizes: self.sizes,
+            address_mode: self.address_mode,
             state: Busy { done: false },
-        }
+        })
     }
 
     /// Erases blocks from the memory chip.
@@ -299,28 +879,68 @@ impl<SPI: Transfer<u8> + Write<u8>, CS: OutputPin> Flash<SPI, CS, Ready> {
     /// # Parameters
     /// * `addr`: The address to start erasing at. If the address is not on a block boundary,
     ///   the lower bits can be ignored in order to make it fit.
-    pub fn erase_blocks(mut self, addr: u32, amount: usize) -> Flash<SPI, CS, Busy> {
+    pub fn erase_blocks(
+        mut self,
+        addr: u32,
+        amount: usize,
+    ) -> Result<Flash<SPI, CS, Busy>, Error<SPI, CS>> {
+        self.ensure_ready()?;
+        self.ensure_writable(addr, amount * self.sizes.block_size)?;
         for c in 0..amount {
-            self.write_enable();
+            self.write_enable()?;
 
             let current_addr: u32 = (addr as usize + c * self.sizes.block_size)
                 .try_into()
                 .unwrap();
-            let mut cmd_buf = [
-                Opcode::BlockErase as u8,
-                (current_addr >> 16) as u8,
-                (current_addr >> 8) as u8,
-                current_addr as u8,
-            ];
-            self.command(&mut cmd_buf);
+            let mut cmd_buf = [0u8; 5];
+            let len = self.serialize_address(self.sizes.block_opcode, current_addr, &mut cmd_buf);
+            self.command(&mut cmd_buf[..len])?;
         }
 
-        Flash {
+        Ok(Flash {
             spi: self.spi,
             cs: self.cs,
             sizes: self.sizes,
+            address_mode: self.address_mode,
             state: Busy { done: false },
+        })
+    }
+
+    /// Erases half-blocks (32 KiB on most parts) from the memory chip.
+    ///
+    /// This is a middle ground between [`erase_sectors`](Self::erase_sectors)
+    /// and [`erase_blocks`](Self::erase_blocks): it clears a large region with
+    /// far fewer commands than sector erases while keeping finer granularity
+    /// than full block erases.
+    ///
+    /// # Parameters
+    /// * `addr`: The address to start erasing at. If the address is not on a half-block boundary,
+    ///   the lower bits can be ignored in order to make it fit.
+    pub fn erase_half_blocks(
+        mut self,
+        addr: u32,
+        amount: usize,
+    ) -> Result<Flash<SPI, CS, Busy>, Error<SPI, CS>> {
+        self.ensure_ready()?;
+        self.ensure_writable(addr, amount * self.sizes.half_block_size)?;
+        for c in 0..amount {
+            self.write_enable()?;
+
+            let current_addr: u32 = (addr as usize + c * self.sizes.half_block_size)
+                .try_into()
+                .unwrap();
+            let mut cmd_buf = [0u8; 5];
+            let len = self.serialize_address(self.sizes.half_block_opcode, current_addr, &mut cmd_buf);
+            self.command(&mut cmd_buf[..len])?;
         }
+
+        Ok(Flash {
+            spi: self.spi,
+            cs: self.cs,
+            sizes: self.sizes,
+            address_mode: self.address_mode,
+            state: Busy { done: false },
+        })
     }
 
     /// Writes bytes onto the memory chip. This method is supposed to assume that the sectors
@@ -329,57 +949,81 @@ impl<SPI: Transfer<u8> + Write<u8>, CS: OutputPin> Flash<SPI, CS, Ready> {
     /// # Parameters
     /// * `addr`: The address to write to.
     /// * `data`: The bytes to write to `addr`.
-    pub fn write_bytes(mut self, addr: u32, data: &[u8]) -> Flash<SPI, CS, Busy> {
+    pub fn write_bytes(
+        mut self,
+        addr: u32,
+        data: &[u8],
+    ) -> Result<Flash<SPI, CS, Busy>, Error<SPI, CS>> {
+        self.ensure_ready()?;
+        self.ensure_writable(addr, data.len())?;
         for (c, chunk) in data.chunks(self.sizes.page_size).enumerate() {
-            self.write_enable();
+            self.write_enable()?;
 
             let current_addr: u32 = (addr as usize + c * self.sizes.page_size)
                 .try_into()
                 .unwrap();
-            let mut cmd_buf = [
-                Opcode::PageProg as u8,
-                (current_addr >> 16) as u8,
-                (current_addr >> 8) as u8,
-                current_addr as u8,
-            ];
-
-            if self.cs.set_low().is_err() {
-                panic!("flash panic");
-            }
-            if self.spi.transfer(&mut cmd_buf).is_err() {
-                panic!("flash panic");
-            }
-            if self.spi.write(chunk).is_err() {
-                panic!("flash panic");
-            }
-            if self.cs.set_high().is_err() {
-                panic!("flash panic");
-            }
+            let mut cmd_buf = [0u8; 5];
+            let len = self.serialize_address(Opcode::PageProg as u8, current_addr, &mut cmd_buf);
+
+            // Make sure to deassert CS even if a transfer fails.
+            self.cs.set_low().map_err(Error::Gpio)?;
+            let spi_result = self
+                .spi
+                .transfer(&mut cmd_buf[..len])
+                .map(drop)
+                .map_err(Error::Transfer)
+                .and_then(|()| self.spi.write(chunk).map_err(Error::Write));
+            let cs_result = self.cs.set_high().map_err(Error::Gpio);
+            spi_result?;
+            cs_result?;
         }
 
-        Flash {
+        Ok(Flash {
             spi: self.spi,
             cs: self.cs,
             sizes: self.sizes,
+            address_mode: self.address_mode,
             state: Busy { done: false },
-        }
+        })
     }
 
     /// Erases the memory chip fully.
     ///
     /// Warning: Full erase operations can take a significant amount of time.
     /// Check your device's datasheet for precise numbers.
-    pub fn erase_all(mut self) -> Flash<SPI, CS, Busy> {
-        self.write_enable();
+    pub fn erase_all(mut self) -> Result<Flash<SPI, CS, Busy>, Error<SPI, CS>> {
+        self.ensure_ready()?;
+        self.ensure_writable(0, self.sizes.chip_size)?;
+        self.write_enable()?;
         let mut cmd_buf = [Opcode::ChipErase as u8];
-        self.command(&mut cmd_buf);
+        self.command(&mut cmd_buf)?;
 
-        Flash {
+        Ok(Flash {
             spi: self.spi,
             cs: self.cs,
             sizes: self.sizes,
+            address_mode: self.address_mode,
             state: Busy { done: false },
-        }
+        })
+    }
+
+    /// Sets the block-protection region via the status register.
+    ///
+    /// This issues a write-enable and then writes the `BP2`/`BP1`/`BP0` bits
+    /// corresponding to `region`, locking the chosen fraction of the array
+    /// against accidental page-program and erase. The remaining status-register
+    /// bits are cleared.
+    pub fn set_protection(&mut self, region: ProtectionRegion) -> Result<(), Error<SPI, CS>> {
+        self.ensure_ready()?;
+        self.write_enable()?;
+        let mut cmd_buf = [Opcode::WriteStatus as u8, region.to_status_bits()];
+        self.command(&mut cmd_buf)
+    }
+
+    /// Reads back the currently configured block-protection region.
+    pub fn protection(&mut self) -> Result<ProtectionRegion, Error<SPI, CS>> {
+        let status = self.read_status()?;
+        Ok(ProtectionRegion::from_bp((status & Status::PROT).bits() >> 2))
     }
 }
 
@@ -397,4 +1041,90 @@ mod tests {
         assert_eq!(device_id[0], 0x22);
         assert_eq!(device_id[1], 0x08);
     }
+
+    #[test]
+    fn test_decode_bfpt() {
+        // A minimal BFPT fixture: 2 MiB part with 4 KiB/32 KiB/64 KiB erases.
+        let mut dwords = [0u32; 16];
+        // 2nd DWORD: density in bits, bit 31 clear -> value + 1 bits.
+        dwords[1] = (2 * 1024 * 1024 * 8) - 1;
+        // 8th DWORD: erase type 1 (4 KiB @ 0x20), erase type 2 (32 KiB @ 0x52).
+        dwords[7] = (0x20 << 8 | 12) | ((0x52 << 8 | 15) << 16);
+        // 9th DWORD: erase type 3 (64 KiB @ 0xD8), type 4 unused.
+        dwords[8] = 0xD8 << 8 | 16;
+        // 11th DWORD: page size 2^8 = 256 bytes.
+        dwords[10] = 8 << 4;
+
+        let sizes = decode_bfpt(&dwords).unwrap();
+        assert_eq!(sizes.chip_size, 2 * 1024 * 1024);
+        assert_eq!(sizes.page_size, 256);
+        assert_eq!((sizes.sector_size, sizes.sector_opcode), (4 * 1024, 0x20));
+        assert_eq!(
+            (sizes.half_block_size, sizes.half_block_opcode),
+            (32 * 1024, 0x52)
+        );
+        assert_eq!((sizes.block_size, sizes.block_opcode), (64 * 1024, 0xD8));
+    }
+
+    #[test]
+    fn test_serialize_address_three_byte() {
+        let mut buf = [0u8; 5];
+        let len = serialize_address(AddressMode::ThreeByte, 0x03, 0x12_3456, &mut buf);
+        assert_eq!(len, 4);
+        assert_eq!(&buf[..len], &[0x03, 0x12, 0x34, 0x56]);
+    }
+
+    #[test]
+    fn test_serialize_address_four_byte() {
+        let mut buf = [0u8; 5];
+        let len = serialize_address(AddressMode::FourByte, 0x03, 0x1234_5678, &mut buf);
+        assert_eq!(len, 5);
+        assert_eq!(&buf[..len], &[0x03, 0x12, 0x34, 0x56, 0x78]);
+    }
+
+    #[test]
+    fn test_device_lookup() {
+        // A W25Q128 (16 MiB) is recognized and stays in 3-byte addressing.
+        let dev = devices::lookup(0xEF, &[0x40, 0x18]).expect("W25Q128 should be known");
+        assert_eq!(dev.sizes.chip_size, 16 * 1024 * 1024);
+        assert!(!dev.capabilities.four_byte_addressing);
+
+        // The W25Q256 (32 MiB) is flagged as needing 4-byte addressing.
+        let dev = devices::lookup(0xEF, &[0x40, 0x19]).expect("W25Q256 should be known");
+        assert_eq!(dev.sizes.chip_size, 32 * 1024 * 1024);
+        assert!(dev.capabilities.four_byte_addressing);
+
+        // An unknown ID is not found.
+        assert!(devices::lookup(0x00, &[0x00, 0x00]).is_none());
+    }
+
+    #[test]
+    fn test_protection_region_roundtrip() {
+        use ProtectionRegion::*;
+        for region in [
+            None, Upper64th, Upper32nd, Upper16th, Upper8th, Upper4th, UpperHalf, All,
+        ] {
+            // set_protection writes these bits; only PROT bits are set, all
+            // other status bits are written as zero.
+            let bits = region.to_status_bits();
+            assert_eq!(bits & !Status::PROT.bits(), 0);
+            // protection() decodes the same bits back to the region.
+            let decoded = ProtectionRegion::from_bp((bits & Status::PROT.bits()) >> 2);
+            assert_eq!(decoded, region);
+        }
+        // Spot-check the BP2/BP1/BP0 placement.
+        assert_eq!(UpperHalf.to_status_bits(), 0b0001_1000);
+        assert_eq!(All.to_status_bits(), 0b0001_1100);
+    }
+
+    #[test]
+    fn test_decode_bfpt_rejects_malformed() {
+        // Too short to hold a density word.
+        assert!(decode_bfpt(&[0u32; 1]).is_none());
+        // No usable erase type (out-of-range exponent must not panic).
+        let mut dwords = [0u32; 16];
+        dwords[1] = 0xFFFF;
+        dwords[7] = 0xFF; // exponent 0xFF -> ignored
+        assert!(decode_bfpt(&dwords).is_none());
+    }
 }